@@ -1,12 +1,18 @@
+extern crate libc;
+
 use std::env;
+use std::fs::OpenOptions;
 use std::io;
 use std::io::BufReader;
 use std::io::prelude::*;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::process::Command;
 use std::process::Stdio;
 
+#[derive(Debug)]
 pub enum HTTP {
     _400,
+    _408,
     _500,
 }
 
@@ -41,6 +47,150 @@ fn early_exit(line: &str) -> ! {
     std::process::exit(1);
 }
 
+const DEFAULT_READ_TIMEOUT_SECS: u64 = 30;
+
+/// The largest number of request headers `cgid` will accept before giving
+/// up and responding `431 Request Header Fields Too Large`.
+const MAX_HEADER_COUNT: usize = 100;
+
+/// The longest single request line (the initial request line or any one
+/// header line) `cgid` will accept, in bytes, before responding `431`.
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+/// The idle read timeout, in milliseconds, for reading the request from the
+/// client: the number of seconds in `CGID_READ_TIMEOUT`, or
+/// `DEFAULT_READ_TIMEOUT_SECS` if it is unset or not a valid number. The
+/// result is saturated at `i32::MAX` so an implausibly large
+/// `CGID_READ_TIMEOUT` can't overflow into a negative value -- `poll(2)`
+/// treats a negative timeout as "block forever", which would silently
+/// disable the read timeout entirely.
+fn read_timeout_ms() -> i32 {
+    let secs = env::var("CGID_READ_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_READ_TIMEOUT_SECS);
+    secs.saturating_mul(1000).min(i32::MAX as u64) as i32
+}
+
+/// Wraps a reader in its own buffer and enforces an idle read timeout via
+/// `poll(2)` on the underlying file descriptor, polling only when that
+/// buffer is empty and a real syscall is about to happen.
+///
+/// `stdin` here is a UCSPI socket handed to us by `tcpserver`, so a stalled
+/// or slow client would otherwise leave a blocking `read` call hanging
+/// forever and tie up the process. We can't poll before *every*
+/// `read`/`read_line`, though: once the request line and headers have
+/// arrived in one TCP segment, every later read is served out of a buffer
+/// with no new bytes expected from the client, and `poll`-ing the fd there
+/// would just block for the full timeout on an idle-but-healthy
+/// connection. Keeping our own buffer (rather than delegating to an inner
+/// `BufRead`) lets us tell the two cases apart.
+struct TimeoutReader<R> {
+    inner: R,
+    fd: RawFd,
+    timeout_ms: i32,
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+}
+
+impl<R: Read> TimeoutReader<R> {
+    fn new(inner: R, fd: RawFd, timeout_ms: i32) -> Self {
+        const BUFFER_SIZE: usize = 8 * 1024;
+        TimeoutReader {
+            inner: inner,
+            fd: fd,
+            timeout_ms: timeout_ms,
+            buf: vec![0; BUFFER_SIZE],
+            pos: 0,
+            cap: 0,
+        }
+    }
+
+    fn poll(&self) -> io::Result<()> {
+        let mut fds = [libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 }];
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), 1, self.timeout_ms) };
+        if ret == 0 {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"))
+        } else if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Refills the buffer from the underlying fd, polling first since this
+    /// is only called once the buffer is exhausted.
+    fn fill(&mut self) -> io::Result<()> {
+        try!(self.poll());
+        self.cap = try!(self.inner.read(&mut self.buf));
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for TimeoutReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.cap {
+            try!(self.fill());
+        }
+        let n = std::cmp::min(buf.len(), self.cap - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for TimeoutReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.cap {
+            try!(self.fill());
+        }
+        Ok(&self.buf[self.pos..self.cap])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = std::cmp::min(self.pos + amt, self.cap);
+    }
+}
+
+/// Reads one `\n`-terminated line from `reader`, the same as
+/// `BufRead::read_line`, but bails out with an `InvalidData` error as soon
+/// as the line exceeds `max_len` bytes instead of growing the buffer
+/// without bound. Because it works a `fill_buf`/`consume` chunk at a time
+/// rather than reading the whole (possibly unterminated) line up front, an
+/// oversized line is never buffered in full before the limit is enforced.
+fn read_line_limited<R: BufRead>(reader: &mut R, max_len: usize) -> io::Result<String> {
+    let mut buf: Vec<u8> = Vec::new();
+    loop {
+        let (done, used) = {
+            let available = try!(reader.fill_buf());
+            if available.is_empty() {
+                (true, 0)
+            } else {
+                match available.iter().position(|&b| b == b'\n') {
+                    Some(i) => {
+                        buf.extend_from_slice(&available[..i + 1]);
+                        (true, i + 1)
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        (false, available.len())
+                    }
+                }
+            }
+        };
+        reader.consume(used);
+        if buf.len() > max_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+        if done {
+            break;
+        }
+    }
+    String::from_utf8(buf).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid utf8 in line"))
+}
+
 /// Parses header into (key, value) tuple
 ///
 /// # Examples
@@ -122,13 +272,16 @@ pub fn parse_header(line: &String) -> Result<(String, String), ()> {
 /// use std::env;
 ///
 /// let mut content_length: usize = 0;
-/// let result = cgid::set_header("key: value".to_string(), &mut content_length);
+/// let mut chunked: bool = false;
+/// let mut expect_continue: bool = false;
+/// let result = cgid::set_header("key: value".to_string(), &mut content_length, &mut chunked, &mut expect_continue);
 ///
 /// assert!(result.is_ok());
 /// assert_eq!(env::var("HTTP_KEY").unwrap(), "value");
 /// ```
 ///
-pub fn set_header(line: String, content_length: &mut usize) -> Result<(), HTTP> {
+pub fn set_header(line: String, content_length: &mut usize, chunked: &mut bool,
+        expect_continue: &mut bool) -> Result<(), HTTP> {
     let (key, value) = match parse_header(&line) {
         Ok((k, v)) => (k, v),
         Err(_) => return Err(HTTP::_400),
@@ -139,17 +292,99 @@ pub fn set_header(line: String, content_length: &mut usize) -> Result<(), HTTP>
     if env_key == "HTTP_CONTENT_TYPE" {
         env_key = String::from("CONTENT_TYPE");
     } else if env_key == "HTTP_CONTENT_LENGTH" {
+        // RFC 7230 3.3.3 forbids a request from carrying both
+        // Transfer-Encoding and Content-Length: whichever order they
+        // arrive in, don't let a forged/stale Content-Length reach the
+        // child once we know the body is chunked-framed. Reject instead of
+        // silently picking one, since which header "wins" is exactly the
+        // request-smuggling ambiguity the RFC calls out.
+        if *chunked {
+            return Err(HTTP::_400);
+        }
         env_key = String::from("CONTENT_LENGTH");
         match value.parse::<usize>() {
             Ok(n) => { *content_length = n },
             Err(_) => return Err(HTTP::_400),
         }
+    } else if env_key == "HTTP_TRANSFER_ENCODING" {
+        if value.trim().eq_ignore_ascii_case("chunked") {
+            if env::var("CONTENT_LENGTH").is_ok() {
+                return Err(HTTP::_400);
+            }
+            *chunked = true;
+        }
+    } else if env_key == "HTTP_EXPECT" {
+        if value.trim().eq_ignore_ascii_case("100-continue") {
+            *expect_continue = true;
+        }
     }
     debug!("HEADER: {}={}", env_key, value);
     env::set_var(env_key, value);
     Ok(())
 }
 
+/// Reads the CGI response document's header block from `reader`, per RFC
+/// 3875: header lines accumulate until a blank line, which terminates the
+/// block. Each entry is `(normalized key, value, raw "Key: value" line)` so
+/// callers can both inspect well-known headers (`STATUS`, `LOCATION`, ...)
+/// and forward the rest to the client verbatim.
+///
+/// # Examples
+///
+/// ```
+/// use cgid;
+/// use std::io::Cursor;
+///
+/// let mut reader = Cursor::new(&b"Content-Type: text/plain\r\nStatus: 404 Not Found\r\n\r\n"[..]);
+/// let headers = cgid::read_cgi_headers(&mut reader).unwrap();
+///
+/// assert_eq!(headers[0], (String::from("CONTENT_TYPE"), String::from("text/plain"), String::from("Content-Type: text/plain")));
+/// assert_eq!(headers[1].0, "STATUS");
+/// ```
+///
+/// A bare `\n` terminator (no `\r`) is accepted too, and the parsed value
+/// never carries the line terminator:
+///
+/// ```
+/// use cgid;
+/// use std::io::Cursor;
+///
+/// let mut reader = Cursor::new(&b"Status: 404 Not Found\nContent-Type: text/plain\n\n"[..]);
+/// let headers = cgid::read_cgi_headers(&mut reader).unwrap();
+///
+/// assert_eq!(headers[0], (String::from("STATUS"), String::from("404 Not Found"), String::from("Status: 404 Not Found")));
+/// ```
+///
+/// It returns an Err if a header line is malformed:
+///
+/// ```
+/// use cgid;
+/// use std::io::Cursor;
+///
+/// let mut reader = Cursor::new(&b"not-a-header\r\n\r\n"[..]);
+///
+/// assert!(cgid::read_cgi_headers(&mut reader).is_err());
+/// ```
+///
+pub fn read_cgi_headers<R: BufRead>(reader: &mut R) -> Result<Vec<(String, String, String)>, HTTP> {
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => return Err(HTTP::_500),
+            Ok(_) => (),
+            Err(_) => return Err(HTTP::_500),
+        }
+        let trimmed = line.trim_end_matches(|c| c == '\r' || c == '\n').to_string();
+        if trimmed.is_empty() {
+            break;
+        }
+        let (key, value) = try!(parse_header(&trimmed).map_err(|_| HTTP::_500));
+        headers.push((key, value, trimmed));
+    }
+    Ok(headers)
+}
+
 enum Req {
     Method,
     PathInfo,
@@ -157,10 +392,89 @@ enum Req {
     Protocol
 }
 
-fn set_request(line: &String) {
+/// Decodes `%XX` percent-escapes in `input` into their raw bytes.
+///
+/// # Examples
+///
+/// ```
+/// use cgid;
+///
+/// assert_eq!(cgid::percent_decode("/a%20b").unwrap(), "/a b");
+/// ```
+///
+/// It returns an Err if an escape is malformed (a trailing `%`, or one not
+/// followed by two hex digits):
+///
+/// ```
+/// use cgid;
+///
+/// assert!(cgid::percent_decode("/a%2").is_err());
+/// assert!(cgid::percent_decode("/a%zz").is_err());
+/// ```
+///
+pub fn percent_decode(input: &str) -> Result<String, ()> {
+    let bytes = input.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if i + 3 > bytes.len() {
+                return Err(());
+            }
+            let hex = try!(std::str::from_utf8(&bytes[i + 1..i + 3]).map_err(|_| ()));
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(());
+            }
+            let byte = try!(u8::from_str_radix(hex, 16).map_err(|_| ()));
+            out.push(byte);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| ())
+}
+
+/// Reports whether `value` is an absolute URI, i.e. it starts with a
+/// `scheme://` where `scheme` is a letter followed by letters, digits,
+/// `+`, `-` or `.` (RFC 3986). A mere `://` appearing later in the value,
+/// e.g. inside a path or query string, does not count.
+///
+/// # Examples
+///
+/// ```
+/// use cgid;
+///
+/// assert!(cgid::is_absolute_uri("http://example.com/landing"));
+/// assert!(cgid::is_absolute_uri("x-custom+scheme.v1://host"));
+/// ```
+///
+/// A local path whose query string happens to carry a URL is not absolute:
+///
+/// ```
+/// use cgid;
+///
+/// assert!(!cgid::is_absolute_uri("/track?dest=http://example.com/landing"));
+/// assert!(!cgid::is_absolute_uri("not-a-uri"));
+/// ```
+///
+pub fn is_absolute_uri(value: &str) -> bool {
+    let scheme_end = match value.find("://") {
+        Some(i) => i,
+        None => return false,
+    };
+    let scheme = &value[..scheme_end];
+    !scheme.is_empty() &&
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic()) &&
+        scheme.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+}
+
+fn set_request(line: &String) -> Result<(), HTTP> {
     let mut method: Vec<char> = Vec::new();
     let mut path_info: Vec<char> = Vec::new();
     let mut query_string: Vec<char> = Vec::new();
+    let mut request_uri: Vec<char> = Vec::new();
     let mut server_protocol: Vec<char> = Vec::new();
     let mut state = Req::Method;
 
@@ -177,12 +491,14 @@ fn set_request(line: &String) {
             Req::PathInfo => {
                 if c == '?' {
                     state = Req::QueryString;
+                    request_uri.push(c);
                     debug!("PATH_INFO: {}", path_info.iter().cloned().collect::<String>());
                 } else if c == ' ' {
                     state = Req::Protocol;
                     debug!("PATH_INFO: {}", path_info.iter().cloned().collect::<String>());
                 } else {
                     path_info.push(c);
+                    request_uri.push(c);
                 }
             }
             Req::QueryString => {
@@ -191,6 +507,7 @@ fn set_request(line: &String) {
                     debug!("QUERY_STRING: {}", query_string.iter().cloned().collect::<String>());
                 } else {
                     query_string.push(c);
+                    request_uri.push(c);
                 }
             }
             Req::Protocol => {
@@ -203,11 +520,18 @@ fn set_request(line: &String) {
         }
     }
 
+    // PATH_INFO must be decoded per the CGI spec, but QUERY_STRING and
+    // REQUEST_URI are kept exactly as the client sent them.
+    let path_info = try!(percent_decode(&path_info.iter().cloned().collect::<String>())
+        .map_err(|_| HTTP::_400));
+
     env::set_var("REQUEST_METHOD", method.iter().cloned().collect::<String>());
     env::set_var("SCRIPT_NAME", "");
-    env::set_var("PATH_INFO", path_info.iter().cloned().collect::<String>());
+    env::set_var("PATH_INFO", path_info);
     env::set_var("QUERY_STRING", query_string.iter().cloned().collect::<String>());
+    env::set_var("REQUEST_URI", request_uri.iter().cloned().collect::<String>());
     env::set_var("SERVER_PROTOCOL", server_protocol.iter().cloned().collect::<String>());
+    Ok(())
 }
 
 pub fn main() {
@@ -218,37 +542,80 @@ pub fn main() {
         warn!("Defaulting to 127.0.0.1");
         String::from("127.0.0.1")
     }));
+    // An SSL front-end (stunnel, tlsserver, etc.) signals that it terminated
+    // TLS for this connection via SSLPORT or CGID_HTTPS; there's no UCSPI
+    // convention for this, so we support either env var.
+    let https = env::var("SSLPORT").is_ok() || env::var("CGID_HTTPS").is_ok();
     env::set_var("SERVER_PORT", env::var("TCPLOCALPORT").unwrap_or_else(|e| {
         warn!("Couldn't get TCPLOCALPORT (not running under UCSPI?): {}", e);
-        warn!("Defaulting to 80");
-        String::from("80")
+        let default_port = if https { "443" } else { "80" };
+        warn!("Defaulting to {}", default_port);
+        String::from(default_port)
+    }));
+    if https {
+        env::set_var("HTTPS", "on");
+    }
+    env::set_var("REMOTE_ADDR", env::var("TCPREMOTEIP").unwrap_or_else(|e| {
+        warn!("Couldn't get TCPREMOTEIP (not running under UCSPI?): {}", e);
+        String::from("")
+    }));
+    env::set_var("REMOTE_PORT", env::var("TCPREMOTEPORT").unwrap_or_else(|e| {
+        warn!("Couldn't get TCPREMOTEPORT (not running under UCSPI?): {}", e);
+        String::from("")
     }));
+    if let Ok(remote_host) = env::var("TCPREMOTEHOST") {
+        env::set_var("REMOTE_HOST", remote_host);
+    }
 
     let stdin = io::stdin();
+    let lock = stdin.lock();
+    let fd = lock.as_raw_fd();
+    let mut reader = TimeoutReader::new(lock, fd, read_timeout_ms());
 
     let mut content_length: usize = 0;
+    let mut chunked: bool = false;
+    let mut expect_continue: bool = false;
 
     debug!("\n\n\n");
-    let mut req = String::new();
-    stdin.lock().read_line(&mut req).unwrap_or_else(|e| {
+    let req = read_line_limited(&mut reader, MAX_LINE_LEN).unwrap_or_else(|e| {
+        if e.kind() == io::ErrorKind::TimedOut {
+            early_exit("408 Request Timeout");
+        } else if e.kind() == io::ErrorKind::InvalidData {
+            early_exit("431 Request Header Fields Too Large");
+        }
         warn!("WTF how can there not be a line: {}", e);
         early_exit("500 Internal Server Error");
     });
 
-    set_request(&req);
+    match set_request(&req) {
+        Ok(_) => (),
+        Err(HTTP::_400) => early_exit("400 Invalid Request"),
+        Err(_) => early_exit("500 Internal Server Error"),
+    }
     warn!("REQUEST: {}", req);
 
     debug!("Request header set!\n");
 
-    for line in stdin.lock().lines() {
-        let val = line.unwrap_or_else(|e| {
+    let mut header_count: usize = 0;
+    loop {
+        let raw = read_line_limited(&mut reader, MAX_LINE_LEN).unwrap_or_else(|e| {
+            if e.kind() == io::ErrorKind::TimedOut {
+                early_exit("408 Request Timeout");
+            } else if e.kind() == io::ErrorKind::InvalidData {
+                early_exit("431 Request Header Fields Too Large");
+            }
             warn!("WTF how can there not be a line: {}", e);
             early_exit("500 Internal Server Error");
         });
+        let val = raw.trim_end_matches(|c| c == '\r' || c == '\n').to_string();
         if val == "" {
             break;
         }
-        match set_header(val, &mut content_length) {
+        if header_count >= MAX_HEADER_COUNT {
+            early_exit("431 Request Header Fields Too Large");
+        }
+        header_count += 1;
+        match set_header(val, &mut content_length, &mut chunked, &mut expect_continue) {
             Ok(_) => (),
             Err(HTTP::_400) => early_exit("400 Invalid Header"),
             Err(_) => early_exit("500 Internal Server Error"),
@@ -257,6 +624,15 @@ pub fn main() {
 
     debug!("All headers set!\n");
 
+    if expect_continue {
+        debug!("Sending 100 Continue...");
+        print!("HTTP/1.0 100 Continue\r\n\r\n");
+        io::stdout().flush().unwrap_or_else(|e| {
+            warn!("Failed to flush 100 Continue: {}", e);
+            early_exit("500 Internal Server Error");
+        });
+    }
+
     let args: Vec<_> = env::args().collect();
 
     let mut child: Command = Command::new(args[1].clone());
@@ -275,49 +651,73 @@ pub fn main() {
         early_exit("500 Internal Server Error");
     });
     debug!("Writing STDIN to child's STDIN...");
-    copy_exact(&mut io::stdin(), &mut c_stdin, content_length).unwrap_or_else(|e| {
-        warn!("Failed to copy child's STDIN: {}", e);
-        early_exit("500 Internal Server Error");
-    });
+    if chunked {
+        copy_chunked(&mut reader, &mut c_stdin).unwrap_or_else(|e| match e {
+            HTTP::_400 => early_exit("400 Bad Request"),
+            HTTP::_408 => early_exit("408 Request Timeout"),
+            HTTP::_500 => early_exit("500 Internal Server Error"),
+        });
+    } else {
+        copy_exact(&mut reader, &mut c_stdin, content_length).unwrap_or_else(|e| {
+            if e.kind() == io::ErrorKind::TimedOut {
+                early_exit("408 Request Timeout");
+            }
+            warn!("Failed to copy child's STDIN: {}", e);
+            early_exit("500 Internal Server Error");
+        });
+    }
     debug!("Written.");
 
-    // Note that this is where Content-Length would be recorded and passed, but
-    // because it would incur more memory overhead and it would be a hassle, Content-Length is not
-    // supported.  Maybe I'll add support optionally
     let c_stdout = f.stdout.unwrap_or_else(|| {
         warn!("Failed to get child's STDOUT");
         early_exit("500 Internal Server Error");
     });
     let mut reader = BufReader::new(c_stdout);
-    debug!("Writing child's STDOUT to STDOUT...");
-    loop {
-        let mut val = String::new();
-        reader.read_line(&mut val).unwrap_or_else(|e| {
-            warn!("WTF how can there not be a line: {}", e);
-            early_exit("500 Internal Server Error");
-        });
-        let (key, value) = match parse_header(&val) {
-            Ok((k, v)) => (k, v),
-            Err(_) => {
-                warn!("Invalid header: {}", val);
-                early_exit("500 Internal Server Error");
-            }
-        };
-        if key == String::from("STATUS") {
-            print!("HTTP/1.0 {}\r\n", value);
-            // flush buffered headers
-            break;
-        } else {
-            // Buffer skipped headers
+    debug!("Reading CGI response headers...");
+    let headers = read_cgi_headers(&mut reader).unwrap_or_else(|_| {
+        warn!("Invalid CGI response headers");
+        early_exit("500 Internal Server Error");
+    });
+
+    let status = headers.iter().find(|&&(ref k, _, _)| k == "STATUS");
+    let location = headers.iter().find(|&&(ref k, _, _)| k == "LOCATION");
+    let status_line = match status {
+        Some(&(_, ref value, _)) => value.clone(),
+        None => match location {
+            // A Location whose value is an absolute URI is a CGI "client
+            // redirect": the server hands the client a 302 pointing at it.
+            Some(&(_, ref value, _)) if is_absolute_uri(value) => String::from("302 Found"),
+            _ => String::from("200 OK"),
+        },
+    };
+    print!("HTTP/1.0 {}\r\n", status_line);
+    debug!("Writing CGI response headers to STDOUT...");
+    for &(ref key, _, ref line) in &headers {
+        if key == "STATUS" {
+            continue;
         }
+        print!("{}\r\n", line);
     }
-    io::copy(&mut reader, &mut io::stdout()).unwrap_or_else(|e| {
+    print!("\r\n");
+
+    debug!("Writing child's STDOUT to STDOUT...");
+    let mut counting_stdout = CountingWriter { inner: io::stdout(), count: 0 };
+    io::copy(&mut reader, &mut counting_stdout).unwrap_or_else(|e| {
         // XXX: note that if this happens who knows what got written to STDOUT; the 500 may end up
         // in the middle of a file or something crazy like that, but what can you do?
         warn!("Failed to copy child's STDOUT: {}", e);
         early_exit("500 Internal Server Error");
     });
     debug!("Written.");
+
+    log_access(
+        &env::var("REMOTE_ADDR").unwrap_or_default(),
+        req.trim_end_matches(|c| c == '\r' || c == '\n'),
+        status_line.split_whitespace().next().unwrap_or("200"),
+        counting_stdout.count,
+        &env::var("HTTP_REFERER").unwrap_or_default(),
+        &env::var("HTTP_USER_AGENT").unwrap_or_default(),
+    );
 }
 
 fn copy_exact<R: Read, W: Write>(mut reader: R, mut writer: W,
@@ -336,3 +736,165 @@ fn copy_exact<R: Read, W: Write>(mut reader: R, mut writer: W,
     try!(writer.write_all(&buffer[..buffer_left]));
     Ok(())
 }
+
+fn io_err_to_http(e: &io::Error) -> HTTP {
+    if e.kind() == io::ErrorKind::TimedOut {
+        HTTP::_408
+    } else if e.kind() == io::ErrorKind::InvalidData {
+        HTTP::_400
+    } else {
+        HTTP::_500
+    }
+}
+
+/// Streams a `Transfer-Encoding: chunked` body from `reader` to `writer`,
+/// one chunk at a time, never buffering the whole body in memory.
+///
+/// Each chunk is introduced by a line holding its size in hex (optionally
+/// followed by `;`-delimited chunk-extensions, which are ignored), then
+/// that many raw bytes, then a trailing CRLF. A chunk size of zero ends the
+/// body; any trailer headers up to the final blank line are consumed and
+/// discarded.
+///
+/// # Examples
+///
+/// ```
+/// use cgid;
+/// use std::io::Cursor;
+///
+/// let mut reader = Cursor::new(&b"4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n"[..]);
+/// let mut body: Vec<u8> = Vec::new();
+/// cgid::copy_chunked(&mut reader, &mut body).unwrap();
+///
+/// assert_eq!(body, b"Wikipedia");
+/// ```
+///
+/// It returns an Err if a chunk size line isn't valid hex:
+///
+/// ```
+/// use cgid;
+/// use std::io::Cursor;
+///
+/// let mut reader = Cursor::new(&b"not-hex\r\n"[..]);
+/// let mut body: Vec<u8> = Vec::new();
+///
+/// assert!(cgid::copy_chunked(&mut reader, &mut body).is_err());
+/// ```
+///
+/// It also refuses to buffer an unbounded chunk-size or trailer line, so a
+/// client that never sends a `\n` can't exhaust memory:
+///
+/// ```
+/// use cgid;
+/// use std::io::Cursor;
+///
+/// let garbage = vec![b'a'; 64 * 1024];
+/// let mut reader = Cursor::new(garbage);
+/// let mut body: Vec<u8> = Vec::new();
+///
+/// assert!(cgid::copy_chunked(&mut reader, &mut body).is_err());
+/// ```
+///
+pub fn copy_chunked<R: BufRead, W: Write>(mut reader: R, mut writer: W) -> Result<(), HTTP> {
+    const BUFFER_SIZE: usize = 64 * 1024;
+    let mut buffer: Vec<u8> = vec![0; BUFFER_SIZE];
+
+    loop {
+        let size_line = match read_line_limited(&mut reader, MAX_LINE_LEN) {
+            Ok(ref line) if line.is_empty() => return Err(HTTP::_400),
+            Ok(line) => line,
+            Err(ref e) => return Err(io_err_to_http(e)),
+        };
+        let size_str = size_line.trim_end_matches(|c| c == '\r' || c == '\n');
+        let size_str = size_str.split(';').next().unwrap_or(size_str).trim();
+        if !size_str.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(HTTP::_400);
+        }
+        let size = match usize::from_str_radix(size_str, 16) {
+            Ok(n) => n,
+            Err(_) => return Err(HTTP::_400),
+        };
+
+        if size == 0 {
+            loop {
+                let trailer = match read_line_limited(&mut reader, MAX_LINE_LEN) {
+                    Ok(line) => line,
+                    Err(ref e) => return Err(io_err_to_http(e)),
+                };
+                if trailer.is_empty() || trailer == "\r\n" || trailer == "\n" {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+
+        let mut chunk_left = size;
+        while chunk_left > BUFFER_SIZE {
+            if let Err(ref e) = reader.read_exact(&mut buffer) { return Err(io_err_to_http(e)) };
+            if writer.write_all(&buffer).is_err() { return Err(HTTP::_500) };
+            chunk_left -= BUFFER_SIZE;
+        }
+        if let Err(ref e) = reader.read_exact(&mut buffer[..chunk_left]) { return Err(io_err_to_http(e)) };
+        if writer.write_all(&buffer[..chunk_left]).is_err() { return Err(HTTP::_500) };
+
+        let mut crlf = [0u8; 2];
+        if let Err(ref e) = reader.read_exact(&mut crlf) { return Err(io_err_to_http(e)) };
+    }
+}
+
+/// Wraps a writer and counts the bytes that pass through it, so an
+/// `io::copy` of the CGI body can report how many bytes it forwarded
+/// without a separate pass over the data.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Formats the current time as an NCSA/CLF timestamp, e.g.
+/// `29/Jul/2026:12:34:56 +0000`. Always UTC, hence the fixed `+0000` offset.
+fn clf_timestamp() -> String {
+    const MONTHS: [&'static str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun",
+        "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        let t = libc::time(std::ptr::null_mut());
+        libc::gmtime_r(&t, &mut tm);
+    }
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        tm.tm_mday, MONTHS[tm.tm_mon as usize], tm.tm_year + 1900,
+        tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+/// Writes one NCSA combined-format access log line: remote address, the raw
+/// request line, the response status, the number of body bytes forwarded,
+/// and the `Referer`/`User-Agent` headers. Goes to the path in
+/// `CGID_ACCESS_LOG` if set, otherwise to stderr alongside the rest of our
+/// logging.
+fn log_access(remote_addr: &str, request_line: &str, status: &str, bytes: u64,
+        referer: &str, user_agent: &str) {
+    let line = format!("{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+        remote_addr, clf_timestamp(), request_line, status, bytes, referer, user_agent);
+    match env::var("CGID_ACCESS_LOG") {
+        Ok(path) => {
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(mut f) => { let _ = writeln!(f, "{}", line); },
+                Err(e) => warn!("Failed to open access log {}: {}", path, e),
+            }
+        }
+        Err(_) => warn!("{}", line),
+    }
+}